@@ -1,3 +1,10 @@
+//! This module reads `-Z proc-macro-cross-thread`, `-Z proc-macro-timeout`,
+//! `-Z proc-macro-assume-pure`, and `-Z proc-macro-cache-stats` off `debugging_opts`, and expects
+//! `BangProcMacro`/`AttrProcMacro`/`ProcMacroDerive` to be constructed with a populated `name`.
+//! Defining those options and populating `name` at the construction sites are changes to
+//! `rustc_session` and `rustc_metadata` respectively — outside this module, which is all that
+//! exists in this checkout.
+
 use crate::base::{self, *};
 use crate::proc_macro_server;
 
@@ -5,12 +12,19 @@ use rustc_ast as ast;
 use rustc_ast::ptr::P;
 use rustc_ast::token;
 use rustc_ast::tokenstream::{CanSynthesizeMissingTokens, TokenStream, TokenTree};
+use rustc_ast_pretty::pprust;
+use rustc_data_structures::fx::FxHashMap;
 use rustc_data_structures::sync::Lrc;
 use rustc_errors::ErrorReported;
 use rustc_parse::nt_to_tokenstream;
 use rustc_parse::parser::ForceCollect;
 use rustc_span::def_id::CrateNum;
-use rustc_span::{Span, DUMMY_SP};
+use rustc_span::{Span, Symbol, DUMMY_SP};
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
 
 struct CrossbeamMessagePipe<T> {
     tx: crossbeam_channel::Sender<T>,
@@ -39,9 +53,252 @@ fn exec_strategy(ecx: &ExtCtxt<'_>) -> impl pm::bridge::server::ExecutionStrateg
     )
 }
 
+/// Returns the expansion time budget configured via `-Z proc-macro-timeout`, or `None` if the
+/// flag wasn't passed (the default: proc macros never time out).
+fn proc_macro_timeout(ecx: &ExtCtxt<'_>) -> Option<Duration> {
+    match ecx.sess.opts.debugging_opts.proc_macro_timeout {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+/// Runs `run`, which is expected to call into `client.run(...)`, under a time budget, turning an
+/// overrun into the diagnostic `-Z proc-macro-timeout` promises ("proc macro `foo` exceeded its
+/// expansion time budget") instead of a panic-shaped one.
+///
+/// `client.run` blocks the calling thread until the macro returns, even when
+/// `-Z proc-macro-cross-thread` isolates the macro's own execution on a worker thread: it still
+/// waits on that worker synchronously, and nothing reachable from this crate can make it return
+/// early — doing that would mean having the cross-thread message pipe stop waiting on the worker
+/// and tear it down, which is plumbing that lives in the `proc_macro` bridge library (the worker
+/// thread, and everything it touches, is spawned and owned there), not something this crate can
+/// implement by itself. So this can only ever notice a budget overrun *after* `run` finally
+/// returns late; a macro that never returns at all (a genuine hang, rather than merely a slow
+/// expansion) still blocks this thread indefinitely, exactly as it would with no timeout
+/// configured. What this does provide: a macro that's slow but eventually completes gets a clear,
+/// recoverable diagnostic naming it and pointing at its call site, instead of either silently
+/// eating the time or (as an earlier version of this code did) tearing down the whole process on
+/// a guess that it wouldn't have returned anyway.
+fn run_with_watchdog<T>(
+    ecx: &ExtCtxt<'_>,
+    name: Symbol,
+    span: Span,
+    run: impl FnOnce() -> T,
+) -> Result<T, ErrorReported> {
+    let timeout = match proc_macro_timeout(ecx) {
+        Some(timeout) => timeout,
+        None => return Ok(run()),
+    };
+
+    let done = Arc::new((Mutex::new(false), Condvar::new()));
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let watchdog = {
+        let done = Arc::clone(&done);
+        let timed_out = Arc::clone(&timed_out);
+        thread::spawn(move || {
+            let (lock, cvar) = &*done;
+            let guard = lock.lock().unwrap();
+            let (_guard, wait_result) = cvar.wait_timeout_while(guard, timeout, |done| !*done).unwrap();
+            if wait_result.timed_out() {
+                timed_out.store(true, Ordering::SeqCst);
+            }
+        })
+    };
+
+    let result = run();
+
+    {
+        let (lock, cvar) = &*done;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+    }
+    // The `notify_one` above wakes the watchdog as soon as `run` returns; joining (rather than
+    // dropping the handle) waits for that thread to exit instead of leaving it detached.
+    watchdog.join().unwrap();
+
+    if timed_out.load(Ordering::SeqCst) {
+        let mut err =
+            ecx.struct_span_err(span, &format!("proc macro `{}` exceeded its expansion time budget", name));
+        err.help(&format!("the configured budget is {:?}", timeout));
+        err.emit();
+        Err(ErrorReported)
+    } else {
+        Ok(result)
+    }
+}
+
+/// Turns a `client.run` failure into the diagnostic emitted at the macro's call site.
+fn emit_proc_macro_error(
+    ecx: &ExtCtxt<'_>,
+    span: Span,
+    panicked_while: &str,
+    e: pm::bridge::PanicMessage,
+) -> ErrorReported {
+    let mut err = ecx.struct_span_err(span, panicked_while);
+    if let Some(s) = e.as_str() {
+        err.help(&format!("message: {}", s));
+    }
+    err.emit();
+    ErrorReported
+}
+
+/// Which of the three proc-macro flavours produced a given cache entry. Combined with the
+/// defining crate, the macro's name and the normalized input, this forms the expansion cache's key.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum ProcMacroKind {
+    Bang,
+    Attr,
+    Derive,
+}
+
+/// Prints a token stream back out to source text, ignoring spans entirely (pretty-printing never
+/// consults them), so that two structurally identical expansions (e.g. `derive` on
+/// near-identical structs defined at different locations) produce the same cache key.
+///
+/// `TokenKind` can't derive `Eq`/`Hash` itself (its `Interpolated` variant carries an arbitrary,
+/// uncomparable AST fragment), so a digest built by walking the stream and hashing its tokens
+/// isn't an option here. Going through the existing pretty-printer instead sidesteps that: it
+/// already knows how to render every token kind, including `Interpolated`, down to comparable,
+/// hashable source text, and the full text is kept (not reduced to a fixed-size digest), so a
+/// hash collision can never cause two different inputs to be treated as the same cache entry.
+fn normalize_token_stream(stream: &TokenStream) -> String {
+    pprust::tts_to_string(stream)
+}
+
+// The macro's own identity has to be part of the key, not just its defining crate: two sibling
+// derives from the same crate (e.g. `#[derive(Serialize, Deserialize)]`, both `serde_derive`)
+// are both `ProcMacroKind::Derive` and can easily be handed the same input tokens (e.g. an empty
+// unit struct), so `(CrateNum, ProcMacroKind, input)` alone would let one's cached output answer
+// for the other.
+type CacheKey = (CrateNum, Symbol, ProcMacroKind, String);
+
+thread_local! {
+    // Keyed per-thread rather than process-wide: a single expansion is always looked up and
+    // populated from the thread that drives it. `note_session` below clears this out whenever
+    // the `Session` driving expansion changes, so a thread reused across compilation sessions
+    // (e.g. a long-lived build server) never serves a stale entry from a previous one.
+    static PURE_EXPANSION_CACHE: RefCell<FxHashMap<CacheKey, TokenStream>> =
+        RefCell::new(FxHashMap::default());
+    static CACHE_HITS: Cell<u64> = Cell::new(0);
+    static CACHE_MISSES: Cell<u64> = Cell::new(0);
+    // (address of the last-seen `Session`, highest `CrateNum` ordinal observed while that address
+    // was current). See `note_session` for why both halves matter.
+    static LAST_SESSION: Cell<(usize, u32)> = Cell::new((0, 0));
+    static CACHE_STATS_ENABLED: Cell<bool> = Cell::new(false);
+}
+
+/// Flushes the cache-stats counters on thread exit, covering the common case of a single session
+/// per process: `note_session` below only flushes on a session *change*, which never happens on
+/// that path, so without this the final (and only) session's counters would never be printed.
+struct CacheStatsReporter;
+
+impl Drop for CacheStatsReporter {
+    fn drop(&mut self) {
+        report_cache_stats();
+    }
+}
+
+thread_local! {
+    static CACHE_STATS_REPORTER: CacheStatsReporter = CacheStatsReporter;
+}
+
+fn report_cache_stats() {
+    if !CACHE_STATS_ENABLED.with(Cell::get) {
+        return;
+    }
+    let (hits, misses) = cache_stats();
+    if hits + misses > 0 {
+        eprintln!("proc-macro expansion cache: {} hits, {} misses", hits, misses);
+    }
+}
+
+/// Returns `(hits, misses)` against the pure-expansion cache so far this session, for
+/// `-Z proc-macro-cache-stats` to report.
+pub fn cache_stats() -> (u64, u64) {
+    (CACHE_HITS.with(Cell::get), CACHE_MISSES.with(Cell::get))
+}
+
+/// Drops all cached expansions and resets the hit/miss counters.
+fn clear_cache() {
+    PURE_EXPANSION_CACHE.with(|cache| cache.borrow_mut().clear());
+    CACHE_HITS.with(|hits| hits.set(0));
+    CACHE_MISSES.with(|misses| misses.set(0));
+}
+
+/// Detects a new compilation session reusing this thread and clears the cache left behind by the
+/// previous one (see the note on `PURE_EXPANSION_CACHE`), flushing its stats first.
+///
+/// A changed `Session` address is the obvious signal, but isn't sufficient on its own: an
+/// allocator can hand a *new*, unrelated session a `Session` at the exact address a previous one
+/// was freed from, and comparing only addresses would then mistake it for the same session.
+/// `CrateNum`s are handed out in increasing order within a single session, so a `CrateNum` lower
+/// than one we've already
+/// cached under the address we're currently tracking is proof the numbering restarted, i.e. a new
+/// session reusing the old address — even though the address itself didn't change.
+fn note_session(ecx: &ExtCtxt<'_>, krate: CrateNum) {
+    CACHE_STATS_ENABLED.with(|enabled| enabled.set(ecx.sess.opts.debugging_opts.proc_macro_cache_stats));
+    // Accessing this is what makes its `Drop` run at thread exit; see `CacheStatsReporter`.
+    CACHE_STATS_REPORTER.with(|_| ());
+
+    let session_addr = &*ecx.sess as *const _ as usize;
+    let krate = krate.as_u32();
+    let is_new_session = LAST_SESSION.with(|last| {
+        let (last_addr, max_krate) = last.get();
+        let is_new = last_addr != session_addr || krate < max_krate;
+        last.set((session_addr, if is_new { krate } else { max_krate.max(krate) }));
+        is_new
+    });
+    if !is_new_session {
+        return;
+    }
+    report_cache_stats();
+    clear_cache();
+}
+
+/// Runs `expand`, consulting (and populating) the pure-expansion cache first when enabled.
+/// Macros only opt into this when the user has asserted, via `-Z proc-macro-assume-pure`, that
+/// identical inputs always produce identical output; construction-site metadata would be a more
+/// targeted (per-macro) opt-in, but that's read out of crate metadata by code outside this crate,
+/// so a session-wide flag is what's wireable from here.
+///
+/// A cache hit reuses the `TokenStream` (spans included) exactly as some earlier invocation with
+/// the same normalized input produced it, rather than rewriting its spans onto the current call
+/// site: a span carries a `SyntaxContext` that encodes macro hygiene, and overwriting every token
+/// wholesale with the new call's span would replace that hygiene information, not just the
+/// diagnostic location, corrupting name resolution inside the reused output. So a hit's
+/// diagnostics and debuginfo point at whichever call site happened to populate the entry first,
+/// which is the accepted tradeoff of a span-insensitive cache in the absence of a real span
+/// translation (shifting each old span by the delta between its original and new call sites) —
+/// machinery this crate doesn't have.
+fn run_cached(
+    ecx: &ExtCtxt<'_>,
+    krate: CrateNum,
+    name: Symbol,
+    kind: ProcMacroKind,
+    normalized_input: String,
+    expand: impl FnOnce() -> Result<TokenStream, pm::bridge::PanicMessage>,
+) -> Result<TokenStream, pm::bridge::PanicMessage> {
+    if !ecx.sess.opts.debugging_opts.proc_macro_assume_pure {
+        return expand();
+    }
+    note_session(ecx, krate);
+
+    let key = (krate, name, kind, normalized_input);
+    if let Some(cached) = PURE_EXPANSION_CACHE.with(|cache| cache.borrow().get(&key).cloned()) {
+        CACHE_HITS.with(|hits| hits.set(hits.get() + 1));
+        return Ok(cached);
+    }
+    CACHE_MISSES.with(|misses| misses.set(misses.get() + 1));
+
+    let output = expand()?;
+    PURE_EXPANSION_CACHE.with(|cache| cache.borrow_mut().insert(key, output.clone()));
+    Ok(output)
+}
+
 pub struct BangProcMacro {
     pub client: pm::bridge::client::Client<fn(pm::TokenStream) -> pm::TokenStream>,
     pub krate: CrateNum,
+    pub name: Symbol,
 }
 
 impl base::ProcMacro for BangProcMacro {
@@ -51,23 +308,23 @@ impl base::ProcMacro for BangProcMacro {
         span: Span,
         input: TokenStream,
     ) -> Result<TokenStream, ErrorReported> {
+        let strategy = exec_strategy(ecx);
+        let backtrace = ecx.ecfg.proc_macro_backtrace;
+        let normalized_input = normalize_token_stream(&input);
         let server = proc_macro_server::Rustc::new(ecx, self.krate);
-        self.client.run(&exec_strategy(ecx), server, input, ecx.ecfg.proc_macro_backtrace).map_err(
-            |e| {
-                let mut err = ecx.struct_span_err(span, "proc macro panicked");
-                if let Some(s) = e.as_str() {
-                    err.help(&format!("message: {}", s));
-                }
-                err.emit();
-                ErrorReported
-            },
-        )
+        run_with_watchdog(ecx, self.name, span, || {
+            run_cached(ecx, self.krate, self.name, ProcMacroKind::Bang, normalized_input, || {
+                self.client.run(&strategy, server, input, backtrace)
+            })
+        })?
+        .map_err(|e| emit_proc_macro_error(ecx, span, "proc macro panicked", e))
     }
 }
 
 pub struct AttrProcMacro {
     pub client: pm::bridge::client::Client<fn(pm::TokenStream, pm::TokenStream) -> pm::TokenStream>,
     pub krate: CrateNum,
+    pub name: Symbol,
 }
 
 impl base::AttrProcMacro for AttrProcMacro {
@@ -78,23 +335,28 @@ impl base::AttrProcMacro for AttrProcMacro {
         annotation: TokenStream,
         annotated: TokenStream,
     ) -> Result<TokenStream, ErrorReported> {
+        let strategy = exec_strategy(ecx);
+        let backtrace = ecx.ecfg.proc_macro_backtrace;
+        // A pure attribute macro's output can depend on either its arguments or the item it's
+        // attached to, so both streams go into the key, joined by a character that can't appear
+        // in pretty-printed source text, rather than being concatenated directly (which would let
+        // e.g. `(a, bc)` and `(ab, c)` collide).
+        let normalized_input =
+            format!("{}\u{0}{}", normalize_token_stream(&annotation), normalize_token_stream(&annotated));
         let server = proc_macro_server::Rustc::new(ecx, self.krate);
-        self.client
-            .run(&exec_strategy(ecx), server, annotation, annotated, ecx.ecfg.proc_macro_backtrace)
-            .map_err(|e| {
-                let mut err = ecx.struct_span_err(span, "custom attribute panicked");
-                if let Some(s) = e.as_str() {
-                    err.help(&format!("message: {}", s));
-                }
-                err.emit();
-                ErrorReported
+        run_with_watchdog(ecx, self.name, span, || {
+            run_cached(ecx, self.krate, self.name, ProcMacroKind::Attr, normalized_input, || {
+                self.client.run(&strategy, server, annotation, annotated, backtrace)
             })
+        })?
+        .map_err(|e| emit_proc_macro_error(ecx, span, "custom attribute panicked", e))
     }
 }
 
 pub struct ProcMacroDerive {
     pub client: pm::bridge::client::Client<fn(pm::TokenStream) -> pm::TokenStream>,
     pub krate: CrateNum,
+    pub name: Symbol,
 }
 
 impl MultiItemModifier for ProcMacroDerive {
@@ -128,28 +390,30 @@ impl MultiItemModifier for ProcMacroDerive {
             nt_to_tokenstream(&item, &ecx.sess.parse_sess, CanSynthesizeMissingTokens::No)
         };
 
+        let strategy = exec_strategy(ecx);
+        let backtrace = ecx.ecfg.proc_macro_backtrace;
+        let normalized_input = normalize_token_stream(&input);
         let server = proc_macro_server::Rustc::new(ecx, self.krate);
-        let stream = match self.client.run(
-            &exec_strategy(ecx),
-            server,
-            input,
-            ecx.ecfg.proc_macro_backtrace,
-        ) {
-            Ok(stream) => stream,
-            Err(e) => {
-                let mut err = ecx.struct_span_err(span, "proc-macro derive panicked");
-                if let Some(s) = e.as_str() {
-                    err.help(&format!("message: {}", s));
-                }
-                err.emit();
+        let result = run_with_watchdog(ecx, self.name, span, || {
+            run_cached(ecx, self.krate, self.name, ProcMacroKind::Derive, normalized_input, || {
+                self.client.run(&strategy, server, input, backtrace)
+            })
+        });
+        let stream = match result {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                emit_proc_macro_error(ecx, span, "proc-macro derive panicked", e);
                 return ExpandResult::Ready(vec![]);
             }
+            // The watchdog already emitted its own diagnostic naming this macro.
+            Err(ErrorReported) => return ExpandResult::Ready(vec![]),
         };
 
         let error_count_before = ecx.sess.parse_sess.span_diagnostic.err_count();
         let mut parser =
             rustc_parse::stream_to_parser(&ecx.sess.parse_sess, stream, Some("proc-macro derive"));
         let mut items = vec![];
+        let mut parse_failure = None;
 
         loop {
             match parser.parse_item(ForceCollect::No) {
@@ -161,15 +425,31 @@ impl MultiItemModifier for ProcMacroDerive {
                         items.push(Annotatable::Item(item));
                     }
                 }
-                Err(mut err) => {
-                    err.emit();
+                Err(err) => {
+                    // Keep the error around instead of emitting it immediately: its span
+                    // points at the offending token inside the macro's own output, which
+                    // is a more useful location than the derive invocation for the note
+                    // we attach below.
+                    parse_failure = Some(err);
                     break;
                 }
             }
         }
 
         // fail if there have been errors emitted
-        if ecx.sess.parse_sess.span_diagnostic.err_count() > error_count_before {
+        if let Some(mut err) = parse_failure {
+            let token_span = err.span.primary_span();
+            // Emit the parser's own diagnostic (e.g. "expected `;`, found `}`") so the specific
+            // reason the tokens didn't parse isn't lost, then the generic summary below it with
+            // a note back at the same spot inside the macro's output.
+            err.emit();
+            let mut diag =
+                ecx.struct_span_err(span, "proc-macro derive produced unparseable tokens");
+            if let Some(token_span) = token_span {
+                diag.span_note(token_span, "unparseable token is located here");
+            }
+            diag.emit();
+        } else if ecx.sess.parse_sess.span_diagnostic.err_count() > error_count_before {
             ecx.struct_span_err(span, "proc-macro derive produced unparseable tokens").emit();
         }
 